@@ -1,8 +1,12 @@
 //! This module is for NAT Behavior Discovery based on RFC5780.
 //! To use this module, the STUN server side must support the OTHER-ADDRESS and CHANGE-REQUEST attributes.
 use std::collections::HashMap;
+use std::time::Duration;
 
+use async_std::future;
 use async_std::net::{SocketAddr, ToSocketAddrs};
+use async_std::sync::{Arc, Mutex};
+use async_std::task;
 use local_ip_address::list_afinet_netifas;
 
 use super::client::*;
@@ -28,6 +32,14 @@ pub enum NATFilteringType {
     Unknown,
 }
 
+/// Defines whether the NAT supports hairpinning.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Hairpinning {
+    Supported,
+    NotSupported,
+    Unknown,
+}
+
 /// Results of behavior discovery based on NAT mapping behavior.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct NATMappingTypeResult {
@@ -35,6 +47,32 @@ pub struct NATMappingTypeResult {
     pub test2_xor_mapped_addr: Option<SocketAddr>,
     pub test3_xor_mapped_addr: Option<SocketAddr>,
     pub mapping_type: NATMappingType,
+    /// Whether the NAT preserves the source port. `None` when behind no NAT or when the
+    /// source port is not known. It is only populated when the caller bound an explicit
+    /// non-ephemeral local port via [`check_nat_mapping_behavior_bound`]; it is always
+    /// `None` from [`check_nat_mapping_behavior`], which cannot observe the source port.
+    pub port_preservation: Option<bool>,
+}
+
+/// Classic RFC 3489 NAT classification.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NATType {
+    OpenInternet,
+    FullCone,
+    RestrictedCone,
+    PortRestrictedCone,
+    Symmetric,
+    SymmetricFirewall,
+    Blocked,
+    Unknown,
+}
+
+/// Coarse NAT class used by rendezvous/matchmaking systems to pair peers.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NATClass {
+    Unknown,
+    Restricted,
+    Unrestricted,
 }
 
 /// Results of behavior discovery based on NAT filtering behavior.
@@ -45,23 +83,39 @@ pub struct NATFilteringTypeResult {
 }
 
 /// Check NAT mapping behavior.
+///
+/// `port_preservation` in the result is always `None` here: the `Client` does not expose
+/// its OS-assigned source port, so it cannot be compared with the mapped port. Use
+/// [`check_nat_mapping_behavior_bound`] with an explicit non-ephemeral local port to
+/// obtain a port-preservation verdict.
 pub async fn check_nat_mapping_behavior<A: ToSocketAddrs>(
     client: &mut Client,
     stun_addr: A,
+) -> Result<NATMappingTypeResult, STUNClientError> {
+    check_nat_mapping_behavior_inner(client, stun_addr, None).await
+}
+
+// Shared implementation. When `local_addr` is known (the caller bound the client to an
+// explicit address) it is used for a precise NoNAT comparison and, if an explicit local
+// port was bound, to detect port preservation. Otherwise the NIC list is scanned and
+// port preservation is left undeterminable, because the OS-assigned source port is not
+// observable through the public `Client` API.
+async fn check_nat_mapping_behavior_inner<A: ToSocketAddrs>(
+    client: &mut Client,
+    stun_addr: A,
+    local_addr: Option<SocketAddr>,
 ) -> Result<NATMappingTypeResult, STUNClientError> {
     let mut result = NATMappingTypeResult {
         test1_xor_mapped_addr: None,
         test2_xor_mapped_addr: None,
         test3_xor_mapped_addr: None,
         mapping_type: NATMappingType::Unknown,
+        port_preservation: None,
     };
 
-    // get NIC IPs
-    let local_ips = list_afinet_netifas().unwrap();
-
     // Test1
     // Send a Binding request and check the Endpoint mapped to NAT.
-    // Compare with the IP of the NIC and check if it is behind the NAT.
+    // Compare with the local IP and check if it is behind the NAT.
     let t1_res = client.binding_request(&stun_addr, None).await?;
     let other_addr = Attribute::get_other_address(&t1_res).ok_or(
         STUNClientError::NotSupportedError(String::from("OTHER-ADDRESS")),
@@ -69,11 +123,27 @@ pub async fn check_nat_mapping_behavior<A: ToSocketAddrs>(
     result.test1_xor_mapped_addr = Some(Attribute::get_xor_mapped_address(&t1_res).ok_or(
         STUNClientError::NotSupportedError(String::from("XOR-MAPPED-ADDRESS")),
     )?);
-    let addr = result.test1_xor_mapped_addr.unwrap().ip();
-    for (_, local_ip) in local_ips {
-        if local_ip == addr {
-            result.mapping_type = NATMappingType::NoNAT;
-            return Ok(result);
+    let mapped_addr = result.test1_xor_mapped_addr.unwrap();
+
+    // When an explicit local IP is known, compare against it directly; otherwise fall
+    // back to scanning every NIC reported by the OS.
+    let is_no_nat = match local_addr {
+        Some(local) if !local.ip().is_unspecified() => local.ip() == mapped_addr.ip(),
+        _ => list_afinet_netifas()
+            .unwrap()
+            .into_iter()
+            .any(|(_, local_ip)| local_ip == mapped_addr.ip()),
+    };
+    if is_no_nat {
+        result.mapping_type = NATMappingType::NoNAT;
+        return Ok(result);
+    }
+
+    // Detect port preservation by comparing the bound source port with the mapped port.
+    // Only determinable when the caller bound an explicit (non-ephemeral) local port.
+    if let Some(local) = local_addr {
+        if local.port() != 0 {
+            result.port_preservation = Some(local.port() == mapped_addr.port());
         }
     }
 
@@ -109,10 +179,128 @@ pub async fn check_nat_mapping_behavior<A: ToSocketAddrs>(
     Ok(result)
 }
 
-/// Check NAT filtering behavior.
+/// Check whether the NAT supports hairpinning.
+/// First a Binding request learns our own external mapped endpoint (XOR-MAPPED-ADDRESS),
+/// then a Binding request is sent from the same socket directly to that mapped address.
+/// If the response loops back through the NAT it supports hairpinning, if it times out it does not.
+pub async fn check_hairpinning<A: ToSocketAddrs>(
+    client: &mut Client,
+    stun_addr: A,
+) -> Result<Hairpinning, STUNClientError> {
+    // Learn our own external mapped endpoint.
+    let t1_res = client.binding_request(&stun_addr, None).await?;
+    let mapped_addr = Attribute::get_xor_mapped_address(&t1_res).ok_or(
+        STUNClientError::NotSupportedError(String::from("XOR-MAPPED-ADDRESS")),
+    )?;
+
+    // Send a Binding request to the external mapped address instead of the STUN server.
+    // When hairpinning is supported the packet that loops back is our own Binding
+    // *Request* (class = Request), echoed by the NAT; `Client::binding_request` correlates
+    // on transaction id and returns it. Gate explicitly on the class so the assumption is
+    // asserted rather than implicit: a looped Request (or, if a peer replied, a Success
+    // Response) means Supported; any other class is inconclusive; a timeout means the
+    // packet did not loop back.
+    match client.binding_request(&mapped_addr, None).await {
+        Ok(res) => match res.class {
+            Class::Request | Class::SuccessResponse => Ok(Hairpinning::Supported),
+            _ => Ok(Hairpinning::Unknown),
+        },
+        Err(STUNClientError::TimeoutError()) => Ok(Hairpinning::NotSupported),
+        Err(e) => Err(e),
+    }
+}
+
+/// Retransmission policy for discovery tests, following STUN's exponential-backoff
+/// retransmission model (RFC 5389). A CHANGE-REQUEST binding is retransmitted up to
+/// `max_retries` times before a timeout is treated as definitive, so a single dropped
+/// UDP packet does not misclassify an EIF-NAT as address-and-port-dependent.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RetransmissionPolicy {
+    /// Maximum time to wait for a response to a single attempt. `None` leaves `Client`'s
+    /// own receive timeout in effect; `Some` caps it shorter. Only set a sub-second value
+    /// when the links are known to be low-latency, since a value below RTT+jitter makes
+    /// every attempt time out and misclassifies an EIF-NAT as address-and-port-dependent.
+    pub per_test_timeout: Option<Duration>,
+    /// Initial retransmission delay (RTO): how long to wait, after an attempt has timed
+    /// out, before sending the first retransmission.
+    pub initial_rto: Duration,
+    /// Factor the RTO is multiplied by after each retransmission.
+    pub multiplier: u32,
+    /// Maximum number of retransmissions after the first attempt.
+    pub max_retries: u32,
+}
+
+impl Default for RetransmissionPolicy {
+    fn default() -> Self {
+        // The CHANGE-REQUEST tests time out as their *expected* terminal result for
+        // EIF-fail/APDF NATs, so keep the retry count low to avoid blocking for minutes.
+        // Leave the per-test wait at the Client's configured timeout so slow links are
+        // not mistaken for filtering.
+        RetransmissionPolicy {
+            per_test_timeout: None,
+            initial_rto: Duration::from_millis(500),
+            multiplier: 2,
+            max_retries: 2,
+        }
+    }
+}
+
+// Send a binding request, capping each attempt at `per_test_timeout` and retransmitting
+// on timeout according to `policy` before concluding that no response will arrive.
+async fn binding_request_retransmitting<A: ToSocketAddrs>(
+    client: &mut Client,
+    stun_addr: &A,
+    attrs: HashMap<Attribute, Vec<u8>>,
+    policy: &RetransmissionPolicy,
+) -> Result<Message, STUNClientError> {
+    let mut rto = policy.initial_rto;
+    let mut retries = 0;
+    loop {
+        // An elapsed per-test timeout and a `Client` TimeoutError are both "no response".
+        let timed_out = match policy.per_test_timeout {
+            Some(t) => match future::timeout(
+                t,
+                client.binding_request(stun_addr, Some(attrs.clone())),
+            )
+            .await
+            {
+                Ok(Ok(res)) => return Ok(res),
+                Ok(Err(STUNClientError::TimeoutError())) | Err(_) => true,
+                Ok(Err(e)) => return Err(e),
+            },
+            None => match client.binding_request(stun_addr, Some(attrs.clone())).await {
+                Ok(res) => return Ok(res),
+                Err(STUNClientError::TimeoutError()) => true,
+                Err(e) => return Err(e),
+            },
+        };
+        if timed_out {
+            if retries >= policy.max_retries {
+                return Err(STUNClientError::TimeoutError());
+            }
+            task::sleep(rto).await;
+            rto *= policy.multiplier;
+            retries += 1;
+        }
+    }
+}
+
+/// Check NAT filtering behavior using the default [`RetransmissionPolicy`].
 pub async fn check_nat_filtering_behavior<A: ToSocketAddrs>(
     client: &mut Client,
     stun_addr: A,
+) -> Result<NATFilteringTypeResult, STUNClientError> {
+    check_nat_filtering_behavior_with_policy(client, stun_addr, &RetransmissionPolicy::default())
+        .await
+}
+
+/// Check NAT filtering behavior, retransmitting each CHANGE-REQUEST test according
+/// to `policy` before concluding a filtering type. This distinguishes a genuine
+/// "no response" from transient packet loss on lossy links.
+pub async fn check_nat_filtering_behavior_with_policy<A: ToSocketAddrs>(
+    client: &mut Client,
+    stun_addr: A,
+    policy: &RetransmissionPolicy,
 ) -> Result<NATFilteringTypeResult, STUNClientError> {
     // Test1
     // Send a Binding request and check the Endpoint mapped to NAT.
@@ -128,7 +316,7 @@ pub async fn check_nat_filtering_behavior<A: ToSocketAddrs>(
     let mut attrs = HashMap::new();
     let change_request = Attribute::generate_change_request_value(true, true);
     attrs.insert(Attribute::ChangeRequest, change_request);
-    let t2_res = client.binding_request(&stun_addr, Some(attrs)).await;
+    let t2_res = binding_request_retransmitting(client, &stun_addr, attrs, policy).await;
     match t2_res {
         Ok(_) => {
             return Ok(NATFilteringTypeResult {
@@ -151,7 +339,7 @@ pub async fn check_nat_filtering_behavior<A: ToSocketAddrs>(
     let mut attrs = HashMap::new();
     let change_request = Attribute::generate_change_request_value(false, true);
     attrs.insert(Attribute::ChangeRequest, change_request);
-    let t3_res = client.binding_request(&stun_addr, Some(attrs)).await;
+    let t3_res = binding_request_retransmitting(client, &stun_addr, attrs, policy).await;
     match t3_res {
         Ok(_) => {
             return Ok(NATFilteringTypeResult {
@@ -170,3 +358,273 @@ pub async fn check_nat_filtering_behavior<A: ToSocketAddrs>(
         },
     }
 }
+
+/// Classify the NAT into the classic RFC 3489 categories by combining the
+/// RFC 5780 mapping and filtering results.
+pub async fn classify_nat<A: ToSocketAddrs + Clone>(
+    client: &mut Client,
+    stun_addr: A,
+) -> Result<NATType, STUNClientError> {
+    // A total timeout on the first binding request means all STUN traffic is blocked.
+    // Probe it here so that only a genuine Test1 timeout yields `Blocked`: a later
+    // timeout on the OTHER-ADDRESS/alternate-port tests inside the mapping run means
+    // the host is reachable but undeterminable, and is surfaced as an error instead.
+    match client.binding_request(&stun_addr, None).await {
+        Ok(_) => {}
+        Err(STUNClientError::TimeoutError()) => return Ok(NATType::Blocked),
+        Err(e) => return Err(e),
+    }
+
+    let mapping = check_nat_mapping_behavior(client, stun_addr.clone()).await?;
+
+    // Address(AndPort)-dependent mapping is symmetric regardless of filtering.
+    match mapping.mapping_type {
+        NATMappingType::AddressDependent | NATMappingType::AddressAndPortDependent => {
+            return Ok(NATType::Symmetric)
+        }
+        NATMappingType::Unknown => return Ok(NATType::Unknown),
+        _ => {}
+    }
+
+    let filtering = check_nat_filtering_behavior(client, stun_addr).await?;
+
+    let nat_type = match (mapping.mapping_type, filtering.filtering_type) {
+        (NATMappingType::NoNAT, NATFilteringType::EndpointIndependent) => NATType::OpenInternet,
+        (NATMappingType::NoNAT, _) => NATType::SymmetricFirewall,
+        (NATMappingType::EndpointIndependent, NATFilteringType::EndpointIndependent) => {
+            NATType::FullCone
+        }
+        (NATMappingType::EndpointIndependent, NATFilteringType::AddressDependent) => {
+            NATType::RestrictedCone
+        }
+        (NATMappingType::EndpointIndependent, NATFilteringType::AddressAndPortDependent) => {
+            NATType::PortRestrictedCone
+        }
+        _ => NATType::Unknown,
+    };
+    Ok(nat_type)
+}
+
+/// Reduce the detailed filtering behavior to a coarse three-value class for peer matching.
+/// Two `Restricted` peers cannot connect directly and must be relayed, while any pair
+/// involving an `Unrestricted` peer can. Any error that prevents determination yields `Unknown`.
+pub async fn nat_compatibility_class<A: ToSocketAddrs>(
+    client: &mut Client,
+    stun_addr: A,
+) -> NATClass {
+    match check_nat_filtering_behavior(client, stun_addr).await {
+        Ok(res) => match res.filtering_type {
+            NATFilteringType::EndpointIndependent => NATClass::Unrestricted,
+            NATFilteringType::AddressDependent | NATFilteringType::AddressAndPortDependent => {
+                NATClass::Restricted
+            }
+            NATFilteringType::Unknown => NATClass::Unknown,
+        },
+        Err(_) => NATClass::Unknown,
+    }
+}
+
+/// Configuration for a [`NatWatcher`].
+#[derive(Clone, Debug)]
+pub struct NatWatcherConfig {
+    /// STUN server to query.
+    pub stun_addr: String,
+    /// Local address to bind the watcher socket to (e.g. `0.0.0.0:0`).
+    pub bind_addr: String,
+    /// How often to re-run the binding request.
+    pub refresh_interval: Duration,
+}
+
+/// Periodically re-runs a binding request against a STUN server and tracks the
+/// current public `SocketAddr`, reporting only when the mapped address changes.
+/// This lets long-running daemons (dynamic-DNS updaters, overlay VPN nodes) react
+/// to WAN IP changes without polling manually.
+pub struct NatWatcher {
+    config: NatWatcherConfig,
+    current: Arc<Mutex<Option<SocketAddr>>>,
+}
+
+impl NatWatcher {
+    /// Create a new watcher. Call [`NatWatcher::watch`] to start the refresh loop.
+    pub fn new(config: NatWatcherConfig) -> Self {
+        NatWatcher {
+            config,
+            current: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// The most recently observed public address, if any.
+    pub async fn current(&self) -> Option<SocketAddr> {
+        *self.current.lock().await
+    }
+
+    /// Run the refresh loop forever, invoking `on_change` whenever the mapped
+    /// address differs from the previous observation. Transient timeouts are
+    /// ignored; any other error ends the loop.
+    pub async fn watch<F>(&self, mut on_change: F) -> Result<(), STUNClientError>
+    where
+        F: FnMut(SocketAddr),
+    {
+        let mut client = Client::new(&self.config.bind_addr, None).await?;
+        loop {
+            match client.binding_request(&self.config.stun_addr, None).await {
+                Ok(res) => {
+                    if let Some(addr) = Attribute::get_xor_mapped_address(&res) {
+                        let mut current = self.current.lock().await;
+                        if *current != Some(addr) {
+                            *current = Some(addr);
+                            on_change(addr);
+                        }
+                    }
+                }
+                Err(STUNClientError::TimeoutError()) => { /* transient, retry next tick */ }
+                Err(e) => return Err(e),
+            }
+            task::sleep(self.config.refresh_interval).await;
+        }
+    }
+}
+
+/// Like [`check_nat_mapping_behavior`], but binds a fresh client to `bind_addr`
+/// first. Passing `0.0.0.0:0` or `[::]:0` lets callers pin the local interface and
+/// address family. When `bind_addr` carries an explicit (non-wildcard) IP, the
+/// resolved address is used for the NoNAT comparison instead of scanning every NIC,
+/// making it precise on multi-homed hosts.
+pub async fn check_nat_mapping_behavior_bound<A: ToSocketAddrs, B: ToSocketAddrs>(
+    bind_addr: A,
+    stun_addr: B,
+) -> Result<NATMappingTypeResult, STUNClientError> {
+    let local_addr = bind_addr.to_socket_addrs().await.unwrap().next();
+    let mut client = Client::new(bind_addr, None).await?;
+    check_nat_mapping_behavior_inner(&mut client, stun_addr, local_addr).await
+}
+
+/// Like [`check_nat_filtering_behavior`], but binds a fresh client to `bind_addr` first.
+pub async fn check_nat_filtering_behavior_bound<A: ToSocketAddrs, B: ToSocketAddrs>(
+    bind_addr: A,
+    stun_addr: B,
+) -> Result<NATFilteringTypeResult, STUNClientError> {
+    let mut client = Client::new(bind_addr, None).await?;
+    check_nat_filtering_behavior(&mut client, stun_addr).await
+}
+
+/// Per-family results from a dual-stack mapping discovery run.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DualStackMappingResult {
+    pub ipv4: Option<NATMappingTypeResult>,
+    pub ipv6: Option<NATMappingTypeResult>,
+}
+
+/// Run mapping discovery separately over IPv4 and IPv6 STUN servers, binding each
+/// run to the matching wildcard address. A family is `None` when no server is given
+/// for it or discovery over it fails.
+pub async fn check_nat_mapping_behavior_dual_stack<S4: ToSocketAddrs, S6: ToSocketAddrs>(
+    ipv4_stun_addr: Option<S4>,
+    ipv6_stun_addr: Option<S6>,
+) -> DualStackMappingResult {
+    let ipv4 = match ipv4_stun_addr {
+        Some(addr) => check_nat_mapping_behavior_bound("0.0.0.0:0", addr).await.ok(),
+        None => None,
+    };
+    let ipv6 = match ipv6_stun_addr {
+        Some(addr) => check_nat_mapping_behavior_bound("[::]:0", addr).await.ok(),
+        None => None,
+    };
+    DualStackMappingResult { ipv4, ipv6 }
+}
+
+/// Aggregated mapping discovery across several STUN servers.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MultiServerMappingResult {
+    /// Successful per-server results, in the order the servers were queried.
+    pub per_server: Vec<NATMappingTypeResult>,
+    /// Consensus mapping type (the most severe observed).
+    pub mapping_type: NATMappingType,
+}
+
+/// Aggregated filtering discovery across several STUN servers.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MultiServerFilteringResult {
+    /// Successful per-server results, in the order the servers were queried.
+    pub per_server: Vec<NATFilteringTypeResult>,
+    /// Consensus filtering type (the most severe observed).
+    pub filtering_type: NATFilteringType,
+}
+
+fn mapping_severity(t: NATMappingType) -> u8 {
+    match t {
+        NATMappingType::NoNAT => 0,
+        NATMappingType::Unknown => 0,
+        NATMappingType::EndpointIndependent => 1,
+        NATMappingType::AddressDependent => 2,
+        NATMappingType::AddressAndPortDependent => 3,
+    }
+}
+
+fn filtering_severity(t: NATFilteringType) -> u8 {
+    match t {
+        NATFilteringType::Unknown => 0,
+        NATFilteringType::EndpointIndependent => 1,
+        NATFilteringType::AddressDependent => 2,
+        NATFilteringType::AddressAndPortDependent => 3,
+    }
+}
+
+/// Run mapping discovery against several STUN servers and reconcile the results.
+/// A single server can misclassify mapping due to load balancing, so the consensus
+/// is the most severe mapping observed (address-and-port-dependent behavior on any
+/// server implies symmetric-like NAT). Servers lacking OTHER-ADDRESS support are
+/// skipped rather than failing the whole run.
+pub async fn check_nat_mapping_behavior_multi<A: ToSocketAddrs + Clone>(
+    client: &mut Client,
+    stun_addrs: &[A],
+) -> Result<MultiServerMappingResult, STUNClientError> {
+    let mut per_server = Vec::new();
+    for stun_addr in stun_addrs {
+        match check_nat_mapping_behavior(client, stun_addr.clone()).await {
+            Ok(res) => per_server.push(res),
+            // Skip servers that can't run the full RFC 5780 test set.
+            Err(STUNClientError::NotSupportedError(_)) | Err(STUNClientError::TimeoutError()) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    let mapping_type = per_server
+        .iter()
+        .map(|r| r.mapping_type)
+        .max_by_key(|t| mapping_severity(*t))
+        .unwrap_or(NATMappingType::Unknown);
+
+    Ok(MultiServerMappingResult {
+        per_server,
+        mapping_type,
+    })
+}
+
+/// Filtering equivalent of [`check_nat_mapping_behavior_multi`]. The consensus is
+/// the most severe filtering behavior observed across the reachable servers.
+pub async fn check_nat_filtering_behavior_multi<A: ToSocketAddrs + Clone>(
+    client: &mut Client,
+    stun_addrs: &[A],
+) -> Result<MultiServerFilteringResult, STUNClientError> {
+    let mut per_server = Vec::new();
+    for stun_addr in stun_addrs {
+        match check_nat_filtering_behavior(client, stun_addr.clone()).await {
+            Ok(res) => per_server.push(res),
+            // Skip servers that are unreachable or can't run the test set.
+            Err(STUNClientError::NotSupportedError(_)) | Err(STUNClientError::TimeoutError()) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    let filtering_type = per_server
+        .iter()
+        .map(|r| r.filtering_type)
+        .max_by_key(|t| filtering_severity(*t))
+        .unwrap_or(NATFilteringType::Unknown);
+
+    Ok(MultiServerFilteringResult {
+        per_server,
+        filtering_type,
+    })
+}